@@ -1,33 +1,115 @@
 //! Account deposit is information per user about their balances in the exchange.
 
-use std::collections::HashMap;
 use std::convert::TryInto;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
 use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Balance};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near_bindgen, AccountId, Balance, Gas, Promise,
+    PromiseResult,
+};
 
 use crate::utils::{ext_fungible_token, ERR_NOT_REGISTERED, GAS_FOR_FT_TRANSFER};
 use crate::*;
 
 const MAX_ACCOUNT_LENGTH: u128 = 64;
-const MIN_ACCOUNT_DEPOSIT_LENGTH: u128 = MAX_ACCOUNT_LENGTH + 16 + 4;
+
+/// Base account record: `amount` (u128) plus `open_orders` (u32).
+const ACCOUNT_DEPOSIT_BASE_BYTES: u128 = 16 + 4;
+
+/// Borsh bookkeeping `tokens: UnorderedMap` embeds in the account record itself: its own
+/// storage-prefix `Vec<u8>` (33 bytes) plus a `keys` and a `values` `Vector`, each with a
+/// one-byte-longer derived prefix and a `u64` length.
+const TOKENS_MAP_BASE_BYTES: u128 = (33 + 4) + 2 * (34 + 4 + 8);
+
+const MIN_ACCOUNT_DEPOSIT_LENGTH: u128 = ACCOUNT_DEPOSIT_BASE_BYTES + TOKENS_MAP_BASE_BYTES;
+
+/// One Borsh-serialized account id: 4-byte length prefix plus up to `MAX_ACCOUNT_LENGTH` bytes.
+const ACCOUNT_ID_STORAGE_BYTES: u128 = MAX_ACCOUNT_LENGTH + 4;
+
+/// Per-token storage under `tokens: UnorderedMap`: the account id lives in both the key-index
+/// and the backing keys vector, plus the `Balance` in the values vector.
+const TOKEN_STORAGE_BYTES: u128 = 2 * (ACCOUNT_ID_STORAGE_BYTES + 8) + 16;
+
+/// Storage of one resting `Order` (see `crate::orderbook`): three account ids, id, side tag,
+/// four `u128` fields (price plus three amounts), plus its `LookupMap` key and book queue/heap
+/// slot.
+const ORDER_STORAGE_BYTES: u128 = 3 * ACCOUNT_ID_STORAGE_BYTES + 8 + 1 + 4 * 16 + 8 + 8 + 16;
+
+/// Gas to allow for the `exchange_callback_post_withdraw` callback itself.
+const GAS_FOR_RESOLVE_WITHDRAW: Gas = 20_000_000_000_000;
+
+#[ext_contract(ext_self)]
+pub trait ExtSelf {
+    /// Resolves a withdraw after the `ft_transfer` promise settles. Re-credits `sender_id`'s
+    /// `AccountDeposit` with `amount` of `token_id` if the transfer failed.
+    fn exchange_callback_post_withdraw(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    );
+}
+
+/// NEP-145 storage balance of an account: total deposited NEAR and the part of it
+/// that is still available (not locked up by registered token storage).
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// NEP-145 bounds on how much NEAR an account must/can keep deposited for storage.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
 
 /// Account deposits information and storage cost.
-#[derive(BorshSerialize, BorshDeserialize, Default, Clone)]
+///
+/// Token balances live in their own `UnorderedMap`, keyed under a prefix unique to this
+/// account, so a single deposit/withdraw only touches that one entry in storage instead of
+/// reserializing every token balance the account holds.
+#[derive(BorshSerialize, BorshDeserialize)]
 pub struct AccountDeposit {
     /// Native amount sent to the exchange.
     /// Used for storage now, but in future can be used for trading as well.
     pub amount: Balance,
     /// Amounts of various tokens in this account.
-    pub tokens: HashMap<AccountId, Balance>,
+    pub tokens: UnorderedMap<AccountId, Balance>,
+    /// Number of this account's resting limit orders (see `crate::orderbook`), charged
+    /// against storage the same way a registered token is.
+    pub open_orders: u32,
 }
 
 impl AccountDeposit {
+    /// Creates an empty account deposit with a storage prefix unique to `account_id`.
+    pub fn new(account_id: &AccountId) -> Self {
+        Self {
+            amount: 0,
+            tokens: UnorderedMap::new(Self::tokens_prefix(account_id)),
+            open_orders: 0,
+        }
+    }
+
+    /// Unique storage prefix for this account's token map, derived from its hash so it has a
+    /// fixed size regardless of account id length.
+    fn tokens_prefix(account_id: &AccountId) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(33);
+        prefix.push(b't');
+        prefix.extend(env::sha256(account_id.as_bytes()));
+        prefix
+    }
+
     /// Adds amount to the balance of given token while checking that storage is covered.
     pub fn add(&mut self, token: AccountId, amount: Balance) {
-        let prev_amount = *self.tokens.get(&token).unwrap_or(&0);
-        self.tokens.insert(token, prev_amount + amount);
+        let prev_amount = self.tokens.get(&token).unwrap_or(0);
+        self.tokens.insert(&token, &(prev_amount + amount));
         assert!(
             self.storage_usage() <= self.amount,
             "ERR_INSUFFICIENT_STORAGE"
@@ -36,17 +118,19 @@ impl AccountDeposit {
 
     /// Subtract from balance of given token.
     pub fn sub(&mut self, token: AccountId, amount: Balance) {
-        let value = *self
+        let value = self
             .tokens
             .get(&token)
             .expect(&format!("ERR_MISSING_TOKEN:{}", token));
         assert!(value >= amount, format!("ERR_NOT_ENOUGH_TOKEN:{}", token));
-        self.tokens.insert(token, value - amount);
+        self.tokens.insert(&token, &(value - amount));
     }
 
     /// Returns amount of $NEAR to cover storage used by this data structure.
     pub fn storage_usage(&self) -> Balance {
-        (MIN_ACCOUNT_DEPOSIT_LENGTH + self.tokens.len() as u128 * (MAX_ACCOUNT_LENGTH + 16))
+        (MIN_ACCOUNT_DEPOSIT_LENGTH
+            + self.tokens.len() as u128 * TOKEN_STORAGE_BYTES
+            + self.open_orders as u128 * ORDER_STORAGE_BYTES)
             * env::storage_byte_cost()
     }
 
@@ -63,7 +147,7 @@ impl AccountDeposit {
     /// Registers given token and set balance to 0.
     /// Fails if not enough amount to cover new storage usage.
     pub fn register(&mut self, token_id: &AccountId) {
-        self.tokens.insert(token_id.clone(), 0);
+        self.tokens.insert(token_id, &0);
         assert!(
             self.storage_usage() <= self.amount,
             "ERR_INSUFFICIENT_STORAGE"
@@ -75,6 +159,65 @@ impl AccountDeposit {
         let amount = self.tokens.remove(token_id).unwrap_or_default();
         assert_eq!(amount, 0, "ERR_UNREGISTER_NON_ZERO_BALANCE");
     }
+
+    /// Reserves storage for one more resting limit order.
+    /// Fails if the account is already at `max_open_orders` or can't cover the extra storage.
+    pub fn open_order(&mut self, max_open_orders: u32) {
+        assert!(
+            self.open_orders < max_open_orders,
+            "ERR_TOO_MANY_OPEN_ORDERS"
+        );
+        self.open_orders += 1;
+        assert!(
+            self.storage_usage() <= self.amount,
+            "ERR_INSUFFICIENT_STORAGE"
+        );
+    }
+
+    /// Frees the storage reserved for a resting limit order that was filled or cancelled.
+    pub fn close_order(&mut self) {
+        self.open_orders -= 1;
+    }
+}
+
+/// Re-credits `deposits` with `amount` of `token_id` after a failed withdraw transfer,
+/// registering the token again first if it was unregistered in the meantime.
+fn internal_refund_failed_withdraw(
+    deposits: &mut AccountDeposit,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    if deposits.tokens.get(token_id).is_none() {
+        deposits.register(token_id);
+    }
+    deposits.add(token_id.clone(), amount);
+}
+
+/// Splits a `storage_deposit` call's attached `amount` into what to credit toward storage and
+/// what to refund immediately: with `registration_only` set, only enough to cover `min_storage`
+/// is credited on first registration (or nothing at all if already registered). Panics if a
+/// brand-new account's attached deposit doesn't cover `min_storage`.
+fn storage_deposit_split(
+    already_registered: bool,
+    registration_only: bool,
+    amount: Balance,
+    min_storage: Balance,
+) -> (Balance, Balance) {
+    if already_registered {
+        if registration_only {
+            (0, amount)
+        } else {
+            (amount, 0)
+        }
+    } else {
+        assert!(amount >= min_storage, "ERR_DEPOSIT_LESS_THAN_MIN_STORAGE");
+        let refund = if registration_only {
+            amount - min_storage
+        } else {
+            0
+        };
+        (amount - refund, refund)
+    }
 }
 
 #[near_bindgen]
@@ -114,33 +257,250 @@ impl Contract {
     pub fn withdraw(&mut self, token_id: ValidAccountId, amount: U128, unregister: Option<bool>) {
         assert_one_yocto();
         let amount: u128 = amount.into();
+        let token_id: AccountId = token_id.into();
         let sender_id = env::predecessor_account_id();
         let mut deposits = self
             .deposited_amounts
             .get(&sender_id)
             .expect(ERR_NOT_REGISTERED);
-        deposits.sub(token_id.as_ref().clone(), amount);
+        deposits.sub(token_id.clone(), amount);
         if unregister == Some(true) {
-            deposits.unregister(token_id.as_ref());
+            deposits.unregister(&token_id);
         }
         self.deposited_amounts.insert(&sender_id, &deposits);
+        self.internal_send_withdraw(&sender_id, &token_id, amount);
+    }
+
+    /// Withdraws a chosen subset of tokens from the deposits of given user in one call.
+    /// Each token is subtracted up-front so a single failing transfer can't roll back the rest.
+    #[payable]
+    pub fn batch_withdraw(&mut self, tokens: Vec<(ValidAccountId, U128)>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self
+            .deposited_amounts
+            .get(&sender_id)
+            .expect(ERR_NOT_REGISTERED);
+        let tokens: Vec<(AccountId, Balance)> = tokens
+            .into_iter()
+            .map(|(token_id, amount)| (token_id.into(), amount.into()))
+            .collect();
+        for (token_id, amount) in tokens.iter() {
+            deposits.sub(token_id.clone(), *amount);
+        }
+        self.deposited_amounts.insert(&sender_id, &deposits);
+        for (token_id, amount) in tokens {
+            self.internal_send_withdraw(&sender_id, &token_id, amount);
+        }
+    }
+
+    /// Withdraws every token the caller holds a non-zero balance of in one call.
+    /// With `unregister` set, also drops each token's now-empty entry from `AccountDeposit`,
+    /// making it easy to fully close out a position.
+    #[payable]
+    pub fn withdraw_all(&mut self, unregister: Option<bool>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let mut deposits = self
+            .deposited_amounts
+            .get(&sender_id)
+            .expect(ERR_NOT_REGISTERED);
+        let tokens: Vec<(AccountId, Balance)> = deposits
+            .tokens
+            .iter()
+            .filter(|(_, amount)| *amount > 0)
+            .collect();
+        for (token_id, amount) in tokens.iter() {
+            deposits.sub(token_id.clone(), *amount);
+            if unregister == Some(true) {
+                deposits.unregister(token_id);
+            }
+        }
+        self.deposited_amounts.insert(&sender_id, &deposits);
+        for (token_id, amount) in tokens {
+            self.internal_send_withdraw(&sender_id, &token_id, amount);
+        }
+    }
+
+    /// Callback after a withdraw's `ft_transfer` settles. If the transfer failed, re-credits
+    /// `sender_id`'s `AccountDeposit` with `amount` of `token_id`, registering the token again
+    /// if it had been unregistered in the meantime.
+    #[private]
+    pub fn exchange_callback_post_withdraw(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {}
+            PromiseResult::Failed => {
+                // Ft_transfer failed. Revert the withdraw, re-crediting the user's balance.
+                let mut deposits = self
+                    .deposited_amounts
+                    .get(&sender_id)
+                    .expect(ERR_NOT_REGISTERED);
+                internal_refund_failed_withdraw(&mut deposits, &token_id, amount.into());
+                self.deposited_amounts.insert(&sender_id, &deposits);
+                env::log(
+                    format!(
+                        "Account {} withdrawing {} of {} failed. Refunding.",
+                        sender_id,
+                        u128::from(amount),
+                        token_id
+                    )
+                    .as_bytes(),
+                );
+            }
+        }
+    }
+
+    /// Implements NEP-145 `storage_deposit`. Registers `account_id` (the predecessor if not
+    /// given) with the attached deposit, or tops up its balance if already registered.
+    /// With `registration_only` set, refunds everything above the minimum required storage.
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount: Balance = env::attached_deposit();
+        let account_id: AccountId = account_id
+            .map(|a| a.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+        let min_storage = AccountDeposit::min_storage_usage();
+        let existing_deposit = self.deposited_amounts.get(&account_id);
+
+        let (credited, refund) = storage_deposit_split(
+            existing_deposit.is_some(),
+            registration_only,
+            amount,
+            min_storage,
+        );
+        match existing_deposit {
+            Some(mut deposit) if credited > 0 => {
+                deposit.amount += credited;
+                self.deposited_amounts.insert(&account_id, &deposit);
+            }
+            Some(_) => {}
+            None => self.internal_register_account(&account_id, credited),
+        }
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        self.internal_storage_balance_of(&account_id)
+            .expect(ERR_NOT_REGISTERED)
+    }
+
+    /// Implements NEP-145 `storage_withdraw`. Reclaims up to `storage_available()` of the
+    /// caller's deposited NEAR (all of it when `amount` is `None`), without touching any
+    /// registered token balance. Panics if `amount` exceeds the available surplus.
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let mut deposit = self
+            .deposited_amounts
+            .get(&sender_id)
+            .expect(ERR_NOT_REGISTERED);
+        let available = deposit.storage_available();
+        let amount: Balance = amount.map(|a| a.into()).unwrap_or(available);
+        assert!(amount <= available, "ERR_STORAGE_WITHDRAW_TOO_MUCH");
+        deposit.amount -= amount;
+        self.deposited_amounts.insert(&sender_id, &deposit);
+        Promise::new(sender_id).transfer(amount);
+        StorageBalance {
+            total: deposit.amount.into(),
+            available: deposit.storage_available().into(),
+        }
+    }
+
+    /// Implements NEP-145 `storage_unregister`. Removes the caller's `AccountDeposit` and
+    /// refunds its NEAR. Refuses while any token balance is non zero unless `force` is set
+    /// (which drops those balances), and always refuses while any orders are open (see
+    /// `crate::orderbook`), since those still reference this account by id.
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let force = force.unwrap_or(false);
+        let sender_id = env::predecessor_account_id();
+        match self.deposited_amounts.get(&sender_id) {
+            Some(mut deposit) => {
+                assert_eq!(deposit.open_orders, 0, "ERR_STORAGE_UNREGISTER_OPEN_ORDERS");
+                let has_positive_balance = deposit.tokens.values().any(|amount| amount > 0);
+                assert!(
+                    !has_positive_balance || force,
+                    "ERR_STORAGE_UNREGISTER_POSITIVE_BALANCE"
+                );
+                // Drop the per-account token map's own storage before removing the account
+                // entry, since it lives under its own prefix rather than inside it.
+                deposit.tokens.clear();
+                self.deposited_amounts.remove(&sender_id);
+                Promise::new(sender_id).transfer(deposit.amount);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Implements NEP-145 `storage_balance_bounds`. The minimum is the storage cost of an
+    /// account with no tokens registered; there is no maximum.
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: AccountDeposit::min_storage_usage().into(),
+            max: None,
+        }
+    }
+
+    /// Implements NEP-145 `storage_balance_of`.
+    pub fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        self.internal_storage_balance_of(account_id.as_ref())
+    }
+}
+
+impl Contract {
+    /// Returns the NEP-145 storage balance for given account, if it is registered.
+    fn internal_storage_balance_of(&self, account_id: &AccountId) -> Option<StorageBalance> {
+        self.deposited_amounts
+            .get(account_id)
+            .map(|deposit| StorageBalance {
+                total: deposit.amount.into(),
+                available: deposit.storage_available().into(),
+            })
+    }
+
+    /// Schedules the `ft_transfer` promise for a withdraw, followed by the resolve callback
+    /// that re-credits `sender_id` if the transfer fails.
+    fn internal_send_withdraw(&self, sender_id: &AccountId, token_id: &AccountId, amount: Balance) {
         ext_fungible_token::ft_transfer(
-            sender_id.try_into().unwrap(),
+            sender_id.clone().try_into().unwrap(),
             amount.into(),
             None,
-            token_id.as_ref(),
+            token_id,
             1,
             GAS_FOR_FT_TRANSFER,
-        );
+        )
+        .then(ext_self::exchange_callback_post_withdraw(
+            token_id.clone(),
+            sender_id.clone(),
+            amount.into(),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_WITHDRAW,
+        ));
     }
-}
 
-impl Contract {
     /// Registers account in deposited amounts with given amount of $NEAR.
     /// If account already exists, adds amount to it.
     /// This should be used when it's known that storage is prepaid.
     pub(crate) fn internal_register_account(&mut self, account_id: &AccountId, amount: Balance) {
-        let mut deposit_amount = self.deposited_amounts.get(&account_id).unwrap_or_default();
+        let mut deposit_amount = self
+            .deposited_amounts
+            .get(&account_id)
+            .unwrap_or_else(|| AccountDeposit::new(account_id));
         deposit_amount.amount += amount;
         self.deposited_amounts.insert(&account_id, &deposit_amount);
     }
@@ -159,7 +519,7 @@ impl Contract {
             .expect(ERR_NOT_REGISTERED);
         assert!(
             self.whitelisted_tokens.contains(token_id)
-                || account_deposit.tokens.contains_key(token_id),
+                || account_deposit.tokens.get(token_id).is_some(),
             "ERR_TOKEN_NOT_WHITELISTED"
         );
         account_deposit.add(token_id.clone(), amount);
@@ -174,7 +534,151 @@ impl Contract {
     ) -> Balance {
         self.deposited_amounts
             .get(sender_id)
-            .and_then(|d| d.tokens.get(token_id).cloned())
+            .and_then(|d| d.tokens.get(token_id))
             .unwrap_or_default()
     }
 }
+
+// Endpoint guards need a full `Contract` to call against, which this snapshot can't provide (no
+// `lib.rs`), so these cover `AccountDeposit`'s own accounting plus the pure
+// `storage_deposit_split`/`internal_refund_failed_withdraw` helpers those endpoints settle
+// through.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+
+    fn get_context(predecessor: AccountId) -> VMContext {
+        VMContext {
+            current_account_id: "ref-exchange.near".to_string(),
+            signer_account_id: predecessor.clone(),
+            signer_account_pk: vec![0, 1, 2],
+            predecessor_account_id: predecessor,
+            input: vec![],
+            block_index: 0,
+            block_timestamp: 0,
+            account_balance: 0,
+            account_locked_balance: 0,
+            storage_usage: 0,
+            attached_deposit: 0,
+            prepaid_gas: 10u64.pow(18),
+            random_seed: vec![0, 1, 2],
+            is_view: false,
+            output_data_receivers: vec![],
+            epoch_height: 0,
+        }
+    }
+
+    fn alice() -> AccountId {
+        "alice.near".to_string()
+    }
+
+    fn set_context() {
+        testing_env!(get_context(alice()));
+    }
+
+    #[test]
+    fn storage_usage_grows_with_tokens_and_open_orders() {
+        set_context();
+        let mut deposit = AccountDeposit::new(&alice());
+        deposit.amount = 10_000_000_000_000_000_000_000;
+        let base = deposit.storage_usage();
+
+        deposit.register(&"token.near".to_string());
+        let with_token = deposit.storage_usage();
+        assert!(with_token > base);
+
+        deposit.open_orders = 1;
+        assert!(deposit.storage_usage() > with_token);
+    }
+
+    #[test]
+    fn storage_available_shrinks_as_usage_grows() {
+        set_context();
+        let mut deposit = AccountDeposit::new(&alice());
+        deposit.amount = deposit.storage_usage() + 10_000_000_000_000_000_000_000;
+        let available_before = deposit.storage_available();
+
+        deposit.register(&"token.near".to_string());
+        assert!(deposit.storage_available() < available_before);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_OPEN_ORDERS")]
+    fn open_order_rejects_beyond_max() {
+        set_context();
+        let mut deposit = AccountDeposit::new(&alice());
+        deposit.amount = 10_000_000_000_000_000_000_000;
+        deposit.open_order(1);
+        deposit.open_order(1);
+    }
+
+    #[test]
+    fn close_order_frees_its_storage_charge() {
+        set_context();
+        let mut deposit = AccountDeposit::new(&alice());
+        deposit.amount = 10_000_000_000_000_000_000_000;
+        deposit.open_order(5);
+        let used_open = deposit.storage_usage();
+
+        deposit.close_order();
+        assert!(deposit.storage_usage() < used_open);
+    }
+
+    #[test]
+    fn refund_failed_withdraw_registers_token_again_if_unregistered() {
+        set_context();
+        let mut deposit = AccountDeposit::new(&alice());
+        deposit.amount = 10_000_000_000_000_000_000_000;
+        let token = "token.near".to_string();
+        assert!(deposit.tokens.get(&token).is_none());
+
+        internal_refund_failed_withdraw(&mut deposit, &token, 5);
+
+        assert_eq!(deposit.tokens.get(&token), Some(5));
+    }
+
+    #[test]
+    fn refund_failed_withdraw_adds_to_existing_balance() {
+        set_context();
+        let mut deposit = AccountDeposit::new(&alice());
+        deposit.amount = 10_000_000_000_000_000_000_000;
+        let token = "token.near".to_string();
+        deposit.register(&token);
+        deposit.add(token.clone(), 3);
+
+        internal_refund_failed_withdraw(&mut deposit, &token, 5);
+
+        assert_eq!(deposit.tokens.get(&token), Some(8));
+    }
+
+    #[test]
+    fn storage_deposit_split_registration_only_refunds_above_min_on_new_account() {
+        let (credited, refund) = storage_deposit_split(false, true, 1000, 700);
+        assert_eq!((credited, refund), (700, 300));
+    }
+
+    #[test]
+    fn storage_deposit_split_full_fund_credits_everything_on_new_account() {
+        let (credited, refund) = storage_deposit_split(false, false, 1000, 700);
+        assert_eq!((credited, refund), (1000, 0));
+    }
+
+    #[test]
+    fn storage_deposit_split_registration_only_refunds_everything_if_already_registered() {
+        let (credited, refund) = storage_deposit_split(true, true, 1000, 700);
+        assert_eq!((credited, refund), (0, 1000));
+    }
+
+    #[test]
+    fn storage_deposit_split_tops_up_balance_if_already_registered() {
+        let (credited, refund) = storage_deposit_split(true, false, 1000, 700);
+        assert_eq!((credited, refund), (1000, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSIT_LESS_THAN_MIN_STORAGE")]
+    fn storage_deposit_split_rejects_new_account_below_min_storage() {
+        storage_deposit_split(false, false, 699, 700);
+    }
+}
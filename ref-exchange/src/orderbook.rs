@@ -0,0 +1,789 @@
+//! On-chain limit order book layered on top of `AccountDeposit` balances (see
+//! `crate::account_deposit`). Assumes `Contract` also carries `orders: LookupMap<OrderId, Order>`,
+//! `order_books: LookupMap<(AccountId, AccountId), OrderBook>` and `next_order_id: OrderId`.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Balance};
+
+use crate::utils::ERR_NOT_REGISTERED;
+use crate::*;
+
+pub type OrderId = u64;
+
+/// Fixed-point scale for `Order::price`: amount of `quote_token` paid per this many units of
+/// `base_token`.
+const PRICE_DENOM: u128 = 1_000_000_000_000_000_000;
+
+/// Max number of resting orders a single account may have open at once.
+const MAX_OPEN_ORDERS_PER_ACCOUNT: u32 = 20;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+impl OrderSide {
+    fn opposite(self) -> Self {
+        match self {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        }
+    }
+}
+
+/// A resting limit order to trade `base_token` against `quote_token` at `price`.
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub struct Order {
+    pub id: OrderId,
+    pub owner_id: AccountId,
+    pub base_token: AccountId,
+    pub quote_token: AccountId,
+    pub side: OrderSide,
+    pub price: Balance,
+    pub original_amount: Balance,
+    pub remaining_amount: Balance,
+    /// Quote still reserved for a `Buy` order (0, unused, for `Sell`); drawn down per fill and
+    /// refunded exactly on close or cancel instead of recomputed from `price`.
+    pub reserved_quote: Balance,
+}
+
+/// Reverses ordering so a `BinaryHeap<AskKey>` pops the lowest price first.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq)]
+struct AskKey(Balance);
+
+impl PartialOrd for AskKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AskKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+/// A minimal binary max-heap over `Vec<T>`, used instead of `std::collections::BinaryHeap` so
+/// `OrderBook` stays plain-data and Borsh round-trips via its backing `Vec`.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct BinaryHeap<T> {
+    items: Vec<T>,
+}
+
+impl<T: Ord> BinaryHeap<T> {
+    fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    fn peek(&self) -> Option<&T> {
+        self.items.first()
+    }
+
+    fn push(&mut self, item: T) {
+        self.items.push(item);
+        let mut i = self.items.len() - 1;
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.items[i] > self.items[parent] {
+                self.items.swap(i, parent);
+                i = parent;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let last = self.items.len().checked_sub(1)?;
+        self.items.swap(0, last);
+        let item = self.items.pop();
+        let n = self.items.len();
+        let mut i = 0;
+        loop {
+            let (left, right) = (2 * i + 1, 2 * i + 2);
+            let mut largest = i;
+            if left < n && self.items[left] > self.items[largest] {
+                largest = left;
+            }
+            if right < n && self.items[right] > self.items[largest] {
+                largest = right;
+            }
+            if largest == i {
+                break;
+            }
+            self.items.swap(i, largest);
+            i = largest;
+        }
+        item
+    }
+}
+
+/// Resting orders for one directed token pair, split into a price-sorted heap per side plus a
+/// FIFO queue of order ids at each price.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct OrderBook {
+    bids: BinaryHeap<Balance>,
+    asks: BinaryHeap<AskKey>,
+    bid_queues: HashMap<Balance, VecDeque<OrderId>>,
+    ask_queues: HashMap<Balance, VecDeque<OrderId>>,
+}
+
+impl OrderBook {
+    fn new() -> Self {
+        Self {
+            bids: BinaryHeap::new(),
+            asks: BinaryHeap::new(),
+            bid_queues: HashMap::new(),
+            ask_queues: HashMap::new(),
+        }
+    }
+
+    /// Best (highest) resting bid price that still has orders queued, lazily dropping any
+    /// price points that were fully drained.
+    fn best_bid(&mut self) -> Option<Balance> {
+        while let Some(&price) = self.bids.peek() {
+            if self.bid_queues.get(&price).map_or(true, |q| q.is_empty()) {
+                self.bids.pop();
+                self.bid_queues.remove(&price);
+            } else {
+                return Some(price);
+            }
+        }
+        None
+    }
+
+    /// Best (lowest) resting ask price that still has orders queued.
+    fn best_ask(&mut self) -> Option<Balance> {
+        while let Some(&AskKey(price)) = self.asks.peek() {
+            if self.ask_queues.get(&price).map_or(true, |q| q.is_empty()) {
+                self.asks.pop();
+                self.ask_queues.remove(&price);
+            } else {
+                return Some(price);
+            }
+        }
+        None
+    }
+
+    fn queues(&self, side: OrderSide) -> &HashMap<Balance, VecDeque<OrderId>> {
+        match side {
+            OrderSide::Buy => &self.bid_queues,
+            OrderSide::Sell => &self.ask_queues,
+        }
+    }
+
+    fn queues_mut(&mut self, side: OrderSide) -> &mut HashMap<Balance, VecDeque<OrderId>> {
+        match side {
+            OrderSide::Buy => &mut self.bid_queues,
+            OrderSide::Sell => &mut self.ask_queues,
+        }
+    }
+
+    /// Order ids resting at `(side, price)`, in FIFO fill order.
+    fn queue_iter(&self, side: OrderSide, price: Balance) -> impl Iterator<Item = OrderId> + '_ {
+        self.queues(side)
+            .get(&price)
+            .into_iter()
+            .flat_map(|q| q.iter().copied())
+    }
+
+    /// Pops the top price off `side`'s heap only, leaving its queue untouched; pairs with
+    /// `restore_price` to set aside a price the matching loop can't fill (self-trade).
+    fn pop_top_price(&mut self, side: OrderSide) {
+        match side {
+            OrderSide::Buy => {
+                self.bids.pop();
+            }
+            OrderSide::Sell => {
+                self.asks.pop();
+            }
+        }
+    }
+
+    /// Restores a price previously set aside via `pop_top_price`.
+    fn restore_price(&mut self, side: OrderSide, price: Balance) {
+        match side {
+            OrderSide::Buy => self.bids.push(price),
+            OrderSide::Sell => self.asks.push(AskKey(price)),
+        }
+    }
+
+    /// Inserts an unfilled (or partially filled) order's remainder into its own side of the
+    /// book, pushing a new price point only if this price wasn't already resting.
+    fn rest(&mut self, order: &Order) {
+        let price = order.price;
+        let is_new_price = {
+            let queue = self.queues_mut(order.side).entry(price).or_default();
+            let is_new_price = queue.is_empty();
+            queue.push_back(order.id);
+            is_new_price
+        };
+        if is_new_price {
+            match order.side {
+                OrderSide::Buy => self.bids.push(price),
+                OrderSide::Sell => self.asks.push(AskKey(price)),
+            }
+        }
+    }
+
+    /// Removes an order id from its resting price queue, dropping the queue entry once empty
+    /// so `rest` doesn't mistake a drained price for one that's still resting.
+    fn remove(&mut self, order: &Order) {
+        let queues = self.queues_mut(order.side);
+        if let Some(queue) = queues.get_mut(&order.price) {
+            queue.retain(|id| *id != order.id);
+            if queue.is_empty() {
+                queues.remove(&order.price);
+            }
+        }
+    }
+}
+
+/// Total resting size at a given price, for the `get_bids`/`get_asks` views.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceLevel {
+    pub price: U128,
+    pub amount: U128,
+}
+
+/// Converts a `base_token` amount into the `quote_token` amount it costs at `price`.
+fn quote_amount(base_amount: Balance, price: Balance) -> Balance {
+    base_amount
+        .checked_mul(price)
+        .expect("ERR_QUOTE_AMOUNT_OVERFLOW")
+        / PRICE_DENOM
+}
+
+/// One resolved fill between `taker` and a resting `maker` order at `maker_price`: draws down
+/// both orders' `remaining_amount` (and whichever side is a `Buy`'s `reserved_quote`, by the
+/// quote paid) and returns the token and amount each side's deposit should be credited. Doesn't
+/// touch either deposit itself.
+fn settle_fill(taker: &mut Order, maker: &mut Order, maker_price: Balance) -> Fill {
+    let amount = taker.remaining_amount.min(maker.remaining_amount);
+    maker.remaining_amount -= amount;
+    taker.remaining_amount -= amount;
+    let quote = quote_amount(amount, maker_price);
+    let (maker_credit, taker_credit) = match taker.side {
+        OrderSide::Buy => {
+            // Taker reserved quote at their own (worse-or-equal) limit price; bank the
+            // improvement from filling at the maker's better price in their still-resting
+            // reservation instead of refunding it now, so it's paid out exactly once, whenever
+            // the order fully closes or is cancelled.
+            taker.reserved_quote -= quote;
+            (
+                (maker.quote_token.clone(), quote),
+                (maker.base_token.clone(), amount),
+            )
+        }
+        OrderSide::Sell => {
+            maker.reserved_quote -= quote;
+            (
+                (maker.base_token.clone(), amount),
+                (maker.quote_token.clone(), quote),
+            )
+        }
+    };
+    Fill {
+        amount,
+        maker_credit,
+        taker_credit,
+    }
+}
+
+/// Result of `settle_fill`: the token and amount each side's deposit should be credited with.
+struct Fill {
+    amount: Balance,
+    maker_credit: (AccountId, Balance),
+    taker_credit: (AccountId, Balance),
+}
+
+/// Token and amount to refund a cancelled order's reservation: the quote still reserved for a
+/// `Buy` order, or the remaining base amount for a `Sell` order.
+fn cancel_refund(order: &Order) -> (AccountId, Balance) {
+    match order.side {
+        OrderSide::Buy => (order.quote_token.clone(), order.reserved_quote),
+        OrderSide::Sell => (order.base_token.clone(), order.remaining_amount),
+    }
+}
+
+/// What to refund when an order fully closes (fills in entirety, whether on placement or later):
+/// any reserved quote a `Buy` order still has left over from price improvement banked during
+/// fills. Always `None` for `Sell`, whose reservation is exactly `remaining_amount`, already
+/// zero by the time an order closes.
+fn close_refund(order: &Order) -> Option<(AccountId, Balance)> {
+    if order.side == OrderSide::Buy && order.reserved_quote > 0 {
+        Some((order.quote_token.clone(), order.reserved_quote))
+    } else {
+        None
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Places a limit order to trade `amount` of `base_token` against `quote_token` at `price`
+    /// (units of `quote_token` per `PRICE_DENOM` units of `base_token`). Matches immediately
+    /// against the opposing side while the incoming price crosses the resting price, filling at
+    /// the resting order's price, then rests any unfilled remainder on the caller's own side.
+    #[payable]
+    pub fn place_limit_order(
+        &mut self,
+        base_token: ValidAccountId,
+        quote_token: ValidAccountId,
+        side: OrderSide,
+        amount: U128,
+        price: U128,
+    ) -> OrderId {
+        assert_one_yocto();
+        let base_token: AccountId = base_token.into();
+        let quote_token: AccountId = quote_token.into();
+        assert_ne!(base_token, quote_token, "ERR_SAME_TOKEN");
+        let amount: Balance = amount.into();
+        let price: Balance = price.into();
+        assert!(amount > 0, "ERR_ZERO_AMOUNT");
+        assert!(price > 0, "ERR_ZERO_PRICE");
+
+        let owner_id = env::predecessor_account_id();
+        let mut deposits = self
+            .deposited_amounts
+            .get(&owner_id)
+            .expect(ERR_NOT_REGISTERED);
+
+        let (in_token, in_amount) = match side {
+            OrderSide::Buy => (quote_token.clone(), quote_amount(amount, price)),
+            OrderSide::Sell => (base_token.clone(), amount),
+        };
+        deposits.sub(in_token, in_amount);
+        deposits.open_order(MAX_OPEN_ORDERS_PER_ACCOUNT);
+        self.deposited_amounts.insert(&owner_id, &deposits);
+
+        let id = self.next_order_id;
+        self.next_order_id += 1;
+        let mut order = Order {
+            id,
+            owner_id: owner_id.clone(),
+            base_token: base_token.clone(),
+            quote_token: quote_token.clone(),
+            side,
+            price,
+            original_amount: amount,
+            remaining_amount: amount,
+            reserved_quote: if side == OrderSide::Buy { in_amount } else { 0 },
+        };
+
+        let book_key = (base_token.clone(), quote_token.clone());
+        let mut book = self
+            .order_books
+            .get(&book_key)
+            .unwrap_or_else(OrderBook::new);
+        self.internal_match_order(&mut book, &mut order);
+        if order.remaining_amount > 0 {
+            book.rest(&order);
+            self.orders.insert(&id, &order);
+        } else {
+            let mut deposits = self
+                .deposited_amounts
+                .get(&owner_id)
+                .expect(ERR_NOT_REGISTERED);
+            if let Some((token, amount)) = close_refund(&order) {
+                deposits.add(token, amount);
+            }
+            deposits.close_order();
+            self.deposited_amounts.insert(&owner_id, &deposits);
+        }
+        self.order_books.insert(&book_key, &book);
+        id
+    }
+
+    /// Cancels a resting order owned by the caller, returning its reserved funds to their
+    /// available balance and freeing the storage it was charged for.
+    #[payable]
+    pub fn cancel_order(&mut self, order_id: OrderId) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let order = self.orders.get(&order_id).expect("ERR_ORDER_NOT_FOUND");
+        assert_eq!(order.owner_id, owner_id, "ERR_NOT_ORDER_OWNER");
+
+        let book_key = (order.base_token.clone(), order.quote_token.clone());
+        let mut book = self
+            .order_books
+            .get(&book_key)
+            .expect("ERR_ORDER_NOT_FOUND");
+        book.remove(&order);
+        self.order_books.insert(&book_key, &book);
+        self.orders.remove(&order_id);
+
+        let mut deposits = self
+            .deposited_amounts
+            .get(&owner_id)
+            .expect(ERR_NOT_REGISTERED);
+        // Refund exactly what's still reserved, rather than recomputing it from
+        // `remaining_amount` and `price`, so cancelling can never pay out more than the order
+        // actually has left over.
+        let (in_token, in_amount) = cancel_refund(&order);
+        deposits.add(in_token, in_amount);
+        deposits.close_order();
+        self.deposited_amounts.insert(&owner_id, &deposits);
+    }
+
+    /// Returns a resting order by id.
+    pub fn get_order(&self, order_id: OrderId) -> Option<Order> {
+        self.orders.get(&order_id)
+    }
+
+    /// Returns resting bid price levels for `(base_token, quote_token)`, best price first.
+    pub fn get_bids(
+        &self,
+        base_token: ValidAccountId,
+        quote_token: ValidAccountId,
+    ) -> Vec<PriceLevel> {
+        self.internal_price_levels(base_token.into(), quote_token.into(), OrderSide::Buy)
+    }
+
+    /// Returns resting ask price levels for `(base_token, quote_token)`, best price first.
+    pub fn get_asks(
+        &self,
+        base_token: ValidAccountId,
+        quote_token: ValidAccountId,
+    ) -> Vec<PriceLevel> {
+        self.internal_price_levels(base_token.into(), quote_token.into(), OrderSide::Sell)
+    }
+}
+
+impl Contract {
+    fn internal_price_levels(
+        &self,
+        base_token: AccountId,
+        quote_token: AccountId,
+        side: OrderSide,
+    ) -> Vec<PriceLevel> {
+        let book = match self.order_books.get(&(base_token, quote_token)) {
+            Some(book) => book,
+            None => return vec![],
+        };
+        let mut levels: Vec<(Balance, Balance)> = book
+            .queues(side)
+            .iter()
+            .map(|(price, ids)| {
+                let amount = ids
+                    .iter()
+                    .filter_map(|id| self.orders.get(id))
+                    .map(|order| order.remaining_amount)
+                    .sum();
+                (*price, amount)
+            })
+            .filter(|(_, amount)| *amount > 0)
+            .collect();
+        match side {
+            OrderSide::Buy => levels.sort_by(|a, b| b.0.cmp(&a.0)),
+            OrderSide::Sell => levels.sort_by(|a, b| a.0.cmp(&b.0)),
+        }
+        levels
+            .into_iter()
+            .map(|(price, amount)| PriceLevel {
+                price: price.into(),
+                amount: amount.into(),
+            })
+            .collect()
+    }
+
+    /// Walks the opposing side of `book`, filling `taker` while its price crosses the best
+    /// resting price, settling each fill at the resting (maker) order's price so the maker
+    /// never gets worse than what they asked for and the taker keeps any price improvement.
+    ///
+    /// A price point entirely made up of the taker's own resting orders can't be matched (that
+    /// would be a self-trade), but must not stop matching altogether: it's popped off the heap
+    /// for the rest of this call (its order queue is untouched) so deeper, still-crossing price
+    /// points keep getting filled, then restored before returning.
+    fn internal_match_order(&mut self, book: &mut OrderBook, taker: &mut Order) {
+        let maker_side = taker.side.opposite();
+        let mut skipped_self_prices: Vec<Balance> = Vec::new();
+        loop {
+            if taker.remaining_amount == 0 {
+                break;
+            }
+            let maker_price = match taker.side {
+                OrderSide::Buy => book.best_ask(),
+                OrderSide::Sell => book.best_bid(),
+            };
+            let maker_price = match maker_price {
+                Some(price) => price,
+                None => break,
+            };
+            let crosses = match taker.side {
+                OrderSide::Buy => maker_price <= taker.price,
+                OrderSide::Sell => maker_price >= taker.price,
+            };
+            if !crosses {
+                break;
+            }
+            // `best_bid`/`best_ask` already dropped any drained price points, so this price is
+            // guaranteed to still have a queued order, though every one of them may be the
+            // taker's own.
+            let maker_id = book.queue_iter(maker_side, maker_price).find(|id| {
+                self.orders
+                    .get(id)
+                    .map_or(false, |o| o.owner_id != taker.owner_id)
+            });
+            let maker_id = match maker_id {
+                Some(id) => id,
+                None => {
+                    book.pop_top_price(maker_side);
+                    skipped_self_prices.push(maker_price);
+                    continue;
+                }
+            };
+            let mut maker = self.orders.get(&maker_id).expect("ERR_ORDER_NOT_FOUND");
+            let fill = settle_fill(taker, &mut maker, maker_price);
+
+            let mut maker_deposits = self
+                .deposited_amounts
+                .get(&maker.owner_id)
+                .expect(ERR_NOT_REGISTERED);
+            let mut taker_deposits = self
+                .deposited_amounts
+                .get(&taker.owner_id)
+                .expect(ERR_NOT_REGISTERED);
+            maker_deposits.add(fill.maker_credit.0, fill.maker_credit.1);
+            taker_deposits.add(fill.taker_credit.0, fill.taker_credit.1);
+            self.deposited_amounts
+                .insert(&maker.owner_id, &maker_deposits);
+            self.deposited_amounts
+                .insert(&taker.owner_id, &taker_deposits);
+
+            if maker.remaining_amount == 0 {
+                book.remove(&maker);
+                self.orders.remove(&maker_id);
+                let mut maker_deposits = self
+                    .deposited_amounts
+                    .get(&maker.owner_id)
+                    .expect(ERR_NOT_REGISTERED);
+                if let Some((token, amount)) = close_refund(&maker) {
+                    maker_deposits.add(token, amount);
+                }
+                maker_deposits.close_order();
+                self.deposited_amounts
+                    .insert(&maker.owner_id, &maker_deposits);
+            } else {
+                self.orders.insert(&maker_id, &maker);
+            }
+        }
+        for price in skipped_self_prices {
+            book.restore_price(maker_side, price);
+        }
+    }
+}
+
+// No `lib.rs` here to construct a full `Contract` against, so `place_limit_order`/`cancel_order`
+// are covered through the pure `settle_fill`/`cancel_refund`/`close_refund` helpers they settle
+// fills and refunds through, plus `OrderBook` and `quote_amount` below them.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: OrderId, owner: &str, side: OrderSide, price: Balance, amount: Balance) -> Order {
+        Order {
+            id,
+            owner_id: owner.to_string(),
+            base_token: "base".to_string(),
+            quote_token: "quote".to_string(),
+            side,
+            price,
+            original_amount: amount,
+            remaining_amount: amount,
+            reserved_quote: if side == OrderSide::Buy {
+                quote_amount(amount, price)
+            } else {
+                0
+            },
+        }
+    }
+
+    #[test]
+    fn binary_heap_pops_in_priority_order() {
+        let mut heap = BinaryHeap::new();
+        for v in [5u128, 1, 9, 3, 7] {
+            heap.push(v);
+        }
+        let mut popped = Vec::new();
+        while let Some(v) = heap.pop() {
+            popped.push(v);
+        }
+        assert_eq!(popped, vec![9, 7, 5, 3, 1]);
+    }
+
+    #[test]
+    fn best_bid_picks_highest_price_first() {
+        let mut book = OrderBook::new();
+        book.rest(&order(1, "alice", OrderSide::Buy, 100, 5));
+        book.rest(&order(2, "bob", OrderSide::Buy, 120, 5));
+        assert_eq!(book.best_bid(), Some(120));
+        assert_eq!(
+            book.queue_iter(OrderSide::Buy, 120).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn best_ask_picks_lowest_price_first() {
+        let mut book = OrderBook::new();
+        book.rest(&order(1, "alice", OrderSide::Sell, 120, 5));
+        book.rest(&order(2, "bob", OrderSide::Sell, 100, 5));
+        assert_eq!(book.best_ask(), Some(100));
+    }
+
+    #[test]
+    fn remove_drops_the_empty_price_point_so_rest_sees_it_as_new() {
+        let mut book = OrderBook::new();
+        let first = order(1, "alice", OrderSide::Sell, 100, 5);
+        book.rest(&first);
+        book.remove(&first);
+        assert_eq!(book.best_ask(), None);
+
+        // Without dropping the drained queue entry, resting a second order at the same price
+        // would find a present-but-empty queue and skip pushing a fresh heap entry, making the
+        // price unreachable via best_ask/best_bid despite having a live order.
+        let second = order(2, "bob", OrderSide::Sell, 100, 5);
+        book.rest(&second);
+        assert_eq!(book.best_ask(), Some(100));
+        book.remove(&second);
+        assert_eq!(book.best_ask(), None);
+    }
+
+    #[test]
+    fn self_only_price_point_can_be_set_aside_and_restored() {
+        let mut book = OrderBook::new();
+        book.rest(&order(1, "alice", OrderSide::Sell, 100, 5));
+
+        // `internal_match_order` does this when every resting order at the best price belongs
+        // to the taker: the price is unreachable for the rest of that call...
+        assert_eq!(book.best_ask(), Some(100));
+        book.pop_top_price(OrderSide::Sell);
+        assert_eq!(book.best_ask(), None);
+        assert_eq!(
+            book.queue_iter(OrderSide::Sell, 100).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        // ...but its order queue was never touched, so restoring it makes it live again.
+        book.restore_price(OrderSide::Sell, 100);
+        assert_eq!(book.best_ask(), Some(100));
+    }
+
+    #[test]
+    fn quote_amount_floors_down() {
+        assert_eq!(quote_amount(3, PRICE_DENOM / 2), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_QUOTE_AMOUNT_OVERFLOW")]
+    fn quote_amount_rejects_overflow() {
+        quote_amount(Balance::MAX, PRICE_DENOM);
+    }
+
+    #[test]
+    fn settle_fill_buy_taker_banks_price_improvement_in_reserved_quote() {
+        let mut taker = order(1, "alice", OrderSide::Buy, 110 * PRICE_DENOM, 5);
+        let mut maker = order(2, "bob", OrderSide::Sell, 100 * PRICE_DENOM, 5);
+        let original_reserved = taker.reserved_quote;
+
+        let fill = settle_fill(&mut taker, &mut maker, 100 * PRICE_DENOM);
+
+        let quote_fill = quote_amount(5, 100 * PRICE_DENOM);
+        assert_eq!(fill.amount, 5);
+        assert_eq!(fill.maker_credit, ("quote".to_string(), quote_fill));
+        assert_eq!(fill.taker_credit, ("base".to_string(), 5));
+        assert_eq!(taker.remaining_amount, 0);
+        assert_eq!(maker.remaining_amount, 0);
+        // The taker reserved quote at its own (worse) limit price; the fill settles at the
+        // maker's better price, so the saved difference stays banked in reserved_quote rather
+        // than being credited now.
+        assert_eq!(taker.reserved_quote, original_reserved - quote_fill);
+        assert!(taker.reserved_quote > 0);
+    }
+
+    #[test]
+    fn settle_fill_partial_maker_fill_owes_maker_no_close_refund() {
+        let mut taker = order(1, "alice", OrderSide::Buy, 120, 10);
+        let mut maker = order(2, "bob", OrderSide::Sell, 100, 4);
+
+        let fill = settle_fill(&mut taker, &mut maker, 100);
+
+        assert_eq!(fill.amount, 4);
+        assert_eq!(taker.remaining_amount, 6);
+        assert_eq!(maker.remaining_amount, 0);
+        // A filled Sell maker's reservation was exactly `remaining_amount` of base token,
+        // already fully consumed by the fill, so closing it owes no further refund.
+        assert_eq!(close_refund(&maker), None);
+    }
+
+    #[test]
+    fn settle_fill_sell_taker_draws_down_maker_reserved_quote() {
+        let mut taker = order(1, "alice", OrderSide::Sell, 90 * PRICE_DENOM, 5);
+        let mut maker = order(2, "bob", OrderSide::Buy, 100 * PRICE_DENOM, 5);
+        let original_reserved = maker.reserved_quote;
+
+        let fill = settle_fill(&mut taker, &mut maker, 100 * PRICE_DENOM);
+
+        let quote_fill = quote_amount(5, 100 * PRICE_DENOM);
+        assert_eq!(fill.maker_credit, ("base".to_string(), 5));
+        assert_eq!(fill.taker_credit, ("quote".to_string(), quote_fill));
+        assert_eq!(maker.reserved_quote, original_reserved - quote_fill);
+        assert_eq!(taker.reserved_quote, 0);
+    }
+
+    #[test]
+    fn reserved_quote_is_exactly_conserved_across_partial_fills_and_final_refund() {
+        let mut taker = order(1, "alice", OrderSide::Buy, 110 * PRICE_DENOM, 10);
+        let original_reserved = taker.reserved_quote;
+        let mut maker1 = order(2, "bob", OrderSide::Sell, 100 * PRICE_DENOM, 4);
+        let mut maker2 = order(3, "carol", OrderSide::Sell, 105 * PRICE_DENOM, 6);
+
+        settle_fill(&mut taker, &mut maker1, 100 * PRICE_DENOM);
+        settle_fill(&mut taker, &mut maker2, 105 * PRICE_DENOM);
+        assert_eq!(taker.remaining_amount, 0);
+
+        let total_quote_paid =
+            quote_amount(4, 100 * PRICE_DENOM) + quote_amount(6, 105 * PRICE_DENOM);
+        let refund = close_refund(&taker).map(|(_, amount)| amount).unwrap_or(0);
+        assert_eq!(total_quote_paid + refund, original_reserved);
+    }
+
+    #[test]
+    fn cancel_refund_buy_returns_reserved_quote_not_recomputed_from_price() {
+        let mut taker = order(1, "alice", OrderSide::Buy, 100 * PRICE_DENOM, 10);
+        // Diverge reserved_quote from what `remaining_amount * price` would recompute, the way
+        // banked price-improvement does after a fill at a better price.
+        taker.remaining_amount = 6;
+        taker.reserved_quote = quote_amount(6, 90 * PRICE_DENOM);
+        assert_eq!(
+            cancel_refund(&taker),
+            ("quote".to_string(), quote_amount(6, 90 * PRICE_DENOM))
+        );
+    }
+
+    #[test]
+    fn cancel_refund_sell_returns_remaining_base_amount() {
+        let taker = order(1, "alice", OrderSide::Sell, 100, 7);
+        assert_eq!(cancel_refund(&taker), ("base".to_string(), 7));
+    }
+
+    #[test]
+    fn close_refund_skips_zero_and_sell_orders() {
+        let mut buy = order(1, "alice", OrderSide::Buy, 100 * PRICE_DENOM, 5);
+        assert!(close_refund(&buy).is_some());
+
+        buy.reserved_quote = 0;
+        assert_eq!(close_refund(&buy), None);
+
+        let sell = order(2, "bob", OrderSide::Sell, 100, 5);
+        assert_eq!(close_refund(&sell), None);
+    }
+}